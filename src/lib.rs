@@ -1,159 +1,782 @@
-use std::io::Cursor;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Duration;
 
-use reqwest::blocking::Client;
-use symphonia::{core::{io::{MediaSource, MediaSourceStream}, formats::FormatOptions, meta::MetadataOptions, probe::Hint}, default};
-use cpal::{Stream, traits::{HostTrait, DeviceTrait, StreamTrait}, Device, StreamConfig};
-use rb::{Producer, SpscRb, RB, RbConsumer, RbProducer};
+use reqwest::blocking::{Client, Response};
+use symphonia::{core::{
+    codecs::Decoder,
+    formats::{FormatOptions, FormatReader, SeekMode, SeekTo},
+    io::{MediaSource, MediaSourceStream},
+    meta::MetadataOptions,
+    probe::Hint,
+    units::{Time, TimeBase}
+}, default};
+use cpal::{Stream, SampleFormat, FromSample, SizedSample, traits::{HostTrait, DeviceTrait, StreamTrait}, Device, StreamConfig};
+use rb::{Consumer, Producer, SpscRb, RB, RbConsumer, RbProducer};
+use rubato::{Resampler as _, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction};
 use symphonia::core::audio::{SignalSpec, SampleBuffer, AudioBufferRef};
 
 #[cfg_attr(target_os = "android", ndk_glue::main(backtrace = "on"))]
 pub fn main()
 {
     let url = "https://ia800503.us.archive.org/8/items/futuresoundfx-98/futuresoundfx-1.mp3?cnt=0";
-    
-    let response = Client::new().get(url.clone())
-        .header("Range", "bytes=0-")
-        .send()
-        .expect(format!("ERR: Could not open {url}").as_str());
-        
-    let source = Box::new(Cursor::new(response.bytes().unwrap().to_vec()));
 
     loop
     {
-        open(source.clone());
+        let source = Box::new(HttpStream::new(Client::new(), url));
+        let mut player = Player::open(source);
+        if let Err(err) = player.run()
+        {
+            println!("WARN: Playback stopped early. {err}");
+        }
     }
 }
 
-fn open(source:Box<dyn MediaSource>)
+/// A `symphonia` `MediaSource` that streams a remote file over HTTP range
+/// requests instead of buffering it entirely before playback starts.
+///
+/// Bytes read contiguously from the start of the file are kept in `cache` so
+/// re-reading them (e.g. seeking backwards) never re-hits the network. Seeking
+/// forward past the cached region issues a fresh ranged GET and resumes reading
+/// from there; bytes read that way are not cached.
+pub struct HttpStream
+{
+    client:Client,
+    url:String,
+    cache:Vec<u8>,
+    pos:u64,
+    /// The byte offset `body`'s next read will return, which can diverge from
+    /// `pos` after a seek until `read` re-requests from the right place.
+    body_pos:u64,
+    len:Option<u64>,
+    seekable:bool,
+    body:Option<Response>
+}
+
+impl HttpStream
 {
-    let mss = MediaSourceStream::new(source, Default::default());
+    pub fn new(client:Client, url:&str) -> Self
+    {
+        let response = client.get(url)
+            .header("Range", "bytes=0-")
+            .send()
+            .expect(format!("ERR: Could not open {url}").as_str());
+
+        let seekable = response.status() == reqwest::StatusCode::PARTIAL_CONTENT
+            && response.headers().get(reqwest::header::ACCEPT_RANGES).map(|v| v == "bytes").unwrap_or(false);
+
+        let len = response.headers().get(reqwest::header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit('/').next())
+            .and_then(|v| v.parse().ok())
+            .or_else(|| response.content_length());
 
-    let format_options = FormatOptions { enable_gapless: true, ..Default::default() };
-    let metadata_options:MetadataOptions = Default::default();
+        HttpStream
+        {
+            client,
+            url: url.to_string(),
+            cache: Vec::new(),
+            pos: 0,
+            body_pos: 0,
+            len,
+            seekable,
+            body: Some(response)
+        }
+    }
+
+    /// Issues a new ranged GET starting at `pos` and makes it the active body.
+    fn request_from(&mut self, pos:u64)
+    {
+        let response = self.client.get(&self.url)
+            .header("Range", format!("bytes={pos}-"))
+            .send()
+            .expect(format!("ERR: Could not open {}", self.url).as_str());
+
+        self.body = Some(response);
+        self.body_pos = pos;
+    }
+}
 
-    let mut reader = match default::get_probe().format(&Hint::new(), mss, &format_options, &metadata_options)
+impl Read for HttpStream
+{
+    fn read(&mut self, buf:&mut [u8]) -> io::Result<usize>
     {
-        Err(err) => panic!("ERR: Failed to probe source. {err}"),
-        Ok(probed) => probed.format
-    };
+        // Serve from the cache first so re-reading already-seen bytes never hits the network.
+        if self.pos < self.cache.len() as u64
+        {
+            let start = self.pos as usize;
+            let n = (self.cache.len() - start).min(buf.len());
+            buf[..n].copy_from_slice(&self.cache[start..start + n]);
+            self.pos += n as u64;
+            return Ok(n);
+        }
 
-    let track = reader.default_track().unwrap();
-    let track_id = track.id;
+        if self.body.is_none() { return Ok(0); }
 
-    let mut decoder = default::get_codecs().make(&track.codec_params, &Default::default()).unwrap();
-    let mut cpal_output:Option<CpalOutput> = None;
+        // A seek may have moved `pos` without moving the active body (or the body may
+        // be positioned from a previous seek); re-request from `pos` before reading
+        // whenever the two have diverged so we never serve bytes at the wrong offset.
+        if self.body_pos != self.pos
+        {
+            if !self.seekable
+            {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, "ERR: Server does not support ranged requests."));
+            }
+            self.request_from(self.pos);
+        }
 
-    loop
+        let body = self.body.as_mut().unwrap();
+        let n = body.read(buf)?;
+        if n == 0
+        {
+            self.body = None;
+            return Ok(0);
+        }
+
+        // Only the contiguous frontier of the cache can be extended; bytes read
+        // after a forward seek past the cache are served but not cached.
+        if self.pos == self.cache.len() as u64
+        {
+            self.cache.extend_from_slice(&buf[..n]);
+        }
+        self.pos += n as u64;
+        self.body_pos += n as u64;
+
+        Ok(n)
+    }
+}
+
+/// Applies a signed offset to a byte position, erroring instead of wrapping
+/// around on underflow (e.g. seeking before the start of the stream).
+fn apply_offset(base:u64, offset:i64) -> io::Result<u64>
+{
+    let target = if offset >= 0 { base.checked_add(offset as u64) } else { base.checked_sub(offset.unsigned_abs()) };
+    target.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "ERR: Seek target is out of bounds."))
+}
+
+impl Seek for HttpStream
+{
+    fn seek(&mut self, pos:SeekFrom) -> io::Result<u64>
+    {
+        let target = match pos
+        {
+            SeekFrom::Start(p) => p,
+            SeekFrom::Current(p) => apply_offset(self.pos, p)?,
+            SeekFrom::End(p) => {
+                let len = self.len.ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "ERR: Stream length is unknown."))?;
+                apply_offset(len, p)?
+            }
+        };
+
+        // Bytes within the cache are always servable; anything past it needs a fresh
+        // ranged request, which `read` issues lazily so a seek with no following read
+        // doesn't cost a network round trip.
+        if target > self.cache.len() as u64 && !self.seekable
+        {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "ERR: Server does not support ranged requests."));
+        }
+
+        self.pos = target;
+        Ok(self.pos)
+    }
+}
+
+impl MediaSource for HttpStream
+{
+    fn is_seekable(&self) -> bool { self.seekable }
+    fn byte_len(&self) -> Option<u64> { self.len }
+}
+
+/// A reusable player core around a `symphonia` `FormatReader`: decodes packets into
+/// a `CpalOutput`, and exposes pause/resume/seek so playback isn't just a
+/// fire-and-forget loop from start to end.
+pub struct Player
+{
+    reader:Box<dyn FormatReader>,
+    decoder:Box<dyn Decoder>,
+    track_id:u32,
+    time_base:Option<TimeBase>,
+    output:Option<CpalOutput>,
+    /// Timestamp (in the track's time base) just past the end of the last decoded packet.
+    elapsed_ts:u64
+}
+
+impl Player
+{
+    pub fn open(source:Box<dyn MediaSource>) -> Self
+    {
+        let mss = MediaSourceStream::new(source, Default::default());
+
+        let format_options = FormatOptions { enable_gapless: true, ..Default::default() };
+        let metadata_options:MetadataOptions = Default::default();
+
+        let reader = match default::get_probe().format(&Hint::new(), mss, &format_options, &metadata_options)
+        {
+            Err(err) => panic!("ERR: Failed to probe source. {err}"),
+            Ok(probed) => probed.format
+        };
+
+        let track = reader.default_track().unwrap();
+        let track_id = track.id;
+        let time_base = track.codec_params.time_base;
+        let decoder = default::get_codecs().make(&track.codec_params, &Default::default()).unwrap();
+
+        Player { reader, decoder, track_id, time_base, output: None, elapsed_ts: 0 }
+    }
+
+    /// Decodes and plays a single packet, then returns — it never blocks waiting
+    /// for more of the source to arrive beyond that one packet. `Ok(true)` means
+    /// there may be more to decode; `Ok(false)` means the source is exhausted and
+    /// the output has been flushed and paused.
+    ///
+    /// Because this only holds `&mut self` for one packet at a time rather than for
+    /// the whole playback session, a driving loop can call `pause`/`resume`/`seek`
+    /// between `step` calls instead of those controls being unreachable for as
+    /// long as playback runs.
+    pub fn step(&mut self) -> Result<bool, AudioOutputError>
     {
-        // Decode the next packet.
-        let packet = match reader.next_packet()
+        let packet = match self.reader.next_packet()
         {
             Ok(packet) => packet,
-            Err(_) => break
+            Err(_) => {
+                if let Some(output) = self.output.as_mut()
+                {
+                    output.flush()?;
+                    output.pause()?;
+                }
+                return Ok(false);
+            }
         };
 
-        if packet.track_id() != track_id { continue; }
+        if packet.track_id() != self.track_id { return Ok(true); }
+        self.elapsed_ts = packet.ts() + packet.dur();
 
-        match decoder.decode(&packet)
+        match self.decoder.decode(&packet)
         {
             Err(err) => println!("WARN: Failed to decode sound. {err}"),
             Ok(decoded) => {
-                if cpal_output.is_none()
+                if self.output.is_none()
                 {
                     let spec = *decoded.spec();
                     let duration = decoded.capacity() as u64;
-                    cpal_output.replace(CpalOutput::new(spec, duration));
+                    self.output.replace(CpalOutput::new(spec, duration)?);
                 }
 
-                cpal_output.as_mut().unwrap().write(decoded);
+                self.output.as_mut().unwrap().write(decoded, 1.0)?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Calls `step` until the source is exhausted. Convenience for callers that
+    /// don't need to interleave transport controls with decoding.
+    pub fn run(&mut self) -> Result<(), AudioOutputError>
+    {
+        while self.step()? {}
+        Ok(())
+    }
+
+    /// Pauses playback, leaving the decode position intact.
+    pub fn pause(&self) -> Result<(), AudioOutputError>
+    {
+        match self.output.as_ref()
+        {
+            Some(output) => output.pause(),
+            None => Ok(())
+        }
+    }
+
+    /// Resumes playback from where it was paused.
+    pub fn resume(&self) -> Result<(), AudioOutputError>
+    {
+        match self.output.as_ref()
+        {
+            Some(output) => output.resume(),
+            None => Ok(())
+        }
+    }
+
+    /// Seeks to `time`, resetting the decoder and clearing the ring buffer so
+    /// stale samples don't play after the jump.
+    pub fn seek(&mut self, time:Duration) -> symphonia::core::errors::Result<()>
+    {
+        let seeked_to = self.reader.seek(SeekMode::Accurate, SeekTo::Time {
+            time: Time { seconds: time.as_secs(), frac: time.subsec_nanos() as f64 / 1_000_000_000.0 },
+            track_id: Some(self.track_id)
+        })?;
+
+        self.decoder.reset();
+        self.elapsed_ts = seeked_to.actual_ts;
+        if let Some(output) = self.output.as_mut()
+        {
+            output.clear().map_err(|err| {
+                symphonia::core::errors::Error::IoError(io::Error::new(io::ErrorKind::Other, err.to_string()))
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Current audible playback position. The end of the last decoded packet runs
+    /// ahead of what's actually playing by however much audio is still sitting in
+    /// the output's buffers, so that backlog is subtracted out here.
+    pub fn position(&self) -> Duration
+    {
+        let decoded = match self.time_base
+        {
+            Some(time_base) => {
+                let time = time_base.calc_time(self.elapsed_ts);
+                Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac)
             }
+            None => Duration::ZERO
+        };
+
+        let buffered = self.output.as_ref().map(CpalOutput::buffered_duration).unwrap_or(Duration::ZERO);
+        decoded.saturating_sub(buffered)
+    }
+}
+
+/// Deinterleaves `samples` (frame-major, `channels` per frame) into one plane per channel.
+fn deinterleave(samples:&[f32], channels:usize) -> Vec<Vec<f32>>
+{
+    let mut planes = vec![Vec::with_capacity(samples.len() / channels); channels];
+    for frame in samples.chunks_exact(channels)
+    {
+        for (ch, sample) in frame.iter().enumerate()
+        {
+            planes[ch].push(*sample);
+        }
+    }
+    planes
+}
+
+/// Interleaves equal-length channel planes back into a single frame-major buffer.
+fn interleave(planes:&[Vec<f32>]) -> Vec<f32>
+{
+    if planes.is_empty() || planes[0].is_empty() { return Vec::new(); }
+
+    let frames = planes[0].len();
+    let mut samples = Vec::with_capacity(frames * planes.len());
+    for frame in 0..frames
+    {
+        for plane in planes
+        {
+            samples.push(plane[frame]);
         }
     }
+    samples
+}
 
-    cpal_output.unwrap().stream.pause().unwrap();
+/// Converts deinterleaved audio from `from_rate` to `to_rate` with a band-limited sinc
+/// resampler, keeping state across calls so block boundaries don't introduce clicks.
+struct Resampler
+{
+    inner:SincFixedIn<f32>,
+    channels:usize,
+    pending:Vec<Vec<f32>>
 }
+
+impl Resampler
+{
+    fn new(from_rate:u32, to_rate:u32, channels:usize) -> Result<Self, AudioOutputError>
+    {
+        let params = SincInterpolationParameters {
+            sinc_len: 256,
+            f_cutoff: 0.95,
+            interpolation: SincInterpolationType::Linear,
+            oversampling_factor: 256,
+            window: WindowFunction::BlackmanHarris2
+        };
+
+        let ratio = to_rate as f64 / from_rate as f64;
+        let inner = SincFixedIn::<f32>::new(ratio, 2.0, params, 1024, channels)
+            .map_err(|err| AudioOutputError::ResampleError(format!("Failed to create the resampler. {err}")))?;
+
+        Ok(Resampler { inner, channels, pending: vec![Vec::new(); channels] })
+    }
+
+    /// Buffers `planes` and returns however many full chunks could be resampled.
+    /// Leftover input shorter than a chunk carries over to the next call.
+    fn process(&mut self, planes:&[Vec<f32>]) -> Result<Vec<Vec<f32>>, AudioOutputError>
+    {
+        for (ch, plane) in planes.iter().enumerate()
+        {
+            self.pending[ch].extend_from_slice(plane);
+        }
+
+        let mut output = vec![Vec::new(); self.channels];
+        loop
+        {
+            let chunk_size = self.inner.input_frames_next();
+            if self.pending[0].len() < chunk_size { break; }
+
+            let chunk:Vec<&[f32]> = self.pending.iter().map(|c| &c[..chunk_size]).collect();
+            let resampled = self.inner.process(&chunk, None)
+                .map_err(|err| AudioOutputError::ResampleError(format!("Failed to resample audio. {err}")))?;
+
+            for (ch, plane) in resampled.into_iter().enumerate()
+            {
+                output[ch].extend(plane);
+            }
+            for plane in self.pending.iter_mut()
+            {
+                plane.drain(..chunk_size);
+            }
+        }
+
+        Ok(output)
+    }
+
+    /// Pads any remaining buffered input up to a full chunk with silence and resamples
+    /// it, so the tail of a track isn't dropped when playback stops.
+    fn flush(&mut self) -> Result<Vec<Vec<f32>>, AudioOutputError>
+    {
+        if self.pending[0].is_empty() { return Ok(vec![Vec::new(); self.channels]); }
+
+        let chunk_size = self.inner.input_frames_next();
+        for plane in self.pending.iter_mut()
+        {
+            plane.resize(chunk_size, 0.0);
+        }
+
+        let chunk:Vec<&[f32]> = self.pending.iter().map(|c| c.as_slice()).collect();
+        let resampled = self.inner.process(&chunk, None)
+            .map_err(|err| AudioOutputError::ResampleError(format!("Failed to resample audio. {err}")))?;
+
+        for plane in self.pending.iter_mut() { plane.clear(); }
+
+        Ok(resampled)
+    }
+}
+
+/// Errors an `AudioOutput` can return instead of panicking mid-playback.
+#[derive(Debug)]
+pub enum AudioOutputError
+{
+    /// Failed to find or negotiate a usable output device/config.
+    DeviceError(String),
+    /// The output stream could not be built for the negotiated device config.
+    OpenStreamError(cpal::BuildStreamError),
+    /// The output stream was built but failed to start playing.
+    PlayStreamError(cpal::PlayStreamError),
+    /// The stream is no longer usable: its error callback fired (e.g. the device
+    /// was disconnected), or pausing or resuming it failed.
+    StreamClosedError,
+    /// The resampler failed to initialize or to process a chunk of audio.
+    ResampleError(String)
+}
+
+impl fmt::Display for AudioOutputError
+{
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            AudioOutputError::DeviceError(err) => write!(f, "ERR: {err}"),
+            AudioOutputError::OpenStreamError(err) => write!(f, "ERR: Failed to open the output stream. {err}"),
+            AudioOutputError::PlayStreamError(err) => write!(f, "ERR: Failed to play the output stream. {err}"),
+            AudioOutputError::StreamClosedError => write!(f, "ERR: The output stream was closed."),
+            AudioOutputError::ResampleError(err) => write!(f, "ERR: {err}")
+        }
+    }
+}
+
+impl std::error::Error for AudioOutputError {}
+
+/// A playback sink that can be written to and drained before pausing, so
+/// implementations aren't tied to CPAL specifically.
+pub trait AudioOutput
+{
+    fn write(&mut self, decoded:AudioBufferRef, volume:f32) -> Result<(), AudioOutputError>;
+    fn flush(&mut self) -> Result<(), AudioOutputError>;
+}
+
 pub struct CpalOutput
 {
     _device:Device,
-    _config:StreamConfig,
-    _spec:SignalSpec,
+    /// Kept alongside `spec` so the resampler can be rebuilt (e.g. after a seek)
+    /// without re-probing the device.
+    config:StreamConfig,
+    spec:SignalSpec,
     stream:Stream,
     writer:Producer<f32>,
-    sample_buffer:SampleBuffer<f32>
+    ring_buffer:SpscRb<f32>,
+    sample_buffer:SampleBuffer<f32>,
+    channels:usize,
+    /// Resamples from the source rate to the device's rate when the device
+    /// can't be configured to match it (e.g. rate-locked Android/WASAPI outputs).
+    resampler:Option<Resampler>,
+    /// Linear gain applied to every sample inside the CPAL callback, stored as
+    /// `f32::to_bits` so it can be shared with the audio thread without a lock.
+    gain:Arc<AtomicU32>,
+    /// Set by the stream's error callback when the device reports a runtime error.
+    closed:Arc<AtomicBool>
 }
 
 impl CpalOutput
 {
-    fn get_config(spec:SignalSpec) -> (Device, StreamConfig)
+    /// Picks a device config matching the source's channel count and sample rate,
+    /// and reports whatever sample format the device actually wants (f32/i16/u16)
+    /// instead of assuming f32.
+    fn get_config(device:&Device, spec:SignalSpec) -> Result<(StreamConfig, SampleFormat), AudioOutputError>
     {
-        let host = cpal::default_host();
-        let device = host.default_output_device().expect("ERR: Failed to get default output device.");
+        let channels = spec.channels.count() as cpal::ChannelCount;
 
-        let channels = spec.channels.count();
-        let config = cpal::StreamConfig {
-            channels: channels as cpal::ChannelCount,
-            sample_rate: cpal::SampleRate(spec.rate),
-            buffer_size: cpal::BufferSize::Default,
+        let matching = device.supported_output_configs()
+            .map_err(|err| AudioOutputError::DeviceError(format!("Failed to query supported output configs. {err}")))?
+            .find(|c| c.channels() == channels && c.min_sample_rate().0 <= spec.rate && spec.rate <= c.max_sample_rate().0);
+
+        let supported_config = match matching
+        {
+            Some(range) => range.with_sample_rate(cpal::SampleRate(spec.rate)),
+            None => {
+                let default = device.default_output_config()
+                    .map_err(|err| AudioOutputError::DeviceError(format!("Failed to get a default output config. {err}")))?;
+
+                if default.channels() == channels
+                {
+                    default
+                }
+                else
+                {
+                    // Otherwise take any config with the source's channel count, at the
+                    // closest rate it supports (the resampler covers the difference).
+                    let range = device.supported_output_configs()
+                        .map_err(|err| AudioOutputError::DeviceError(format!("Failed to query supported output configs. {err}")))?
+                        .find(|c| c.channels() == channels)
+                        // The device doesn't offer a config matching the source's channel count at
+                        // all; rather than silently frame the interleaved data for the wrong
+                        // channel count, report it instead of producing garbled audio.
+                        .ok_or_else(|| AudioOutputError::DeviceError(format!(
+                            "Device output only supports {} channel(s), source has {channels}.", default.channels()
+                        )))?;
+                    let rate = spec.rate.clamp(range.min_sample_rate().0, range.max_sample_rate().0);
+                    range.with_sample_rate(cpal::SampleRate(rate))
+                }
+            }
         };
 
-        (device, config)
+        Ok((supported_config.config(), supported_config.sample_format()))
+    }
+
+    /// Builds the output stream for a concrete CPAL sample type `T`, applying
+    /// the ramped gain in `f32` before converting into the device's native format.
+    fn build_stream<T>(device:&Device, config:&StreamConfig, reader:Consumer<f32>, gain:Arc<AtomicU32>, closed:Arc<AtomicBool>) -> Result<Stream, AudioOutputError>
+    where T:SizedSample + FromSample<f32>
+    {
+        let mut ramped_gain = 1.0f32;
+        let mut scratch:Vec<f32> = Vec::new();
+
+        device.build_output_stream(
+            config,
+            move |data:&mut [T], _:&cpal::OutputCallbackInfo| {
+                scratch.resize(data.len(), 0.0);
+                let written = reader.read(&mut scratch).unwrap_or(0);
+                scratch[written..].iter_mut().for_each(|s| *s = 0.0);
+
+                // Ramp from the previously applied gain to the current one over
+                // this callback so volume changes don't introduce zipper noise.
+                let target_gain = f32::from_bits(gain.load(Ordering::Relaxed));
+                if written > 0
+                {
+                    let step = (target_gain - ramped_gain) / written as f32;
+                    for sample in scratch[..written].iter_mut()
+                    {
+                        ramped_gain += step;
+                        *sample *= ramped_gain;
+                    }
+                }
+                ramped_gain = target_gain;
+
+                for (out, sample) in data.iter_mut().zip(scratch.iter())
+                {
+                    *out = T::from_sample(*sample);
+                }
+            },
+            move |err| {
+                println!("WARN: An error occurred during the stream. {err}");
+                closed.store(true, Ordering::Relaxed);
+            },
+            None
+        ).map_err(AudioOutputError::OpenStreamError)
     }
 
     /// Starts a new stream on the default device.
     /// Creates a new ring buffer and sample buffer.
-    pub fn new(spec:SignalSpec, duration:u64) -> Self
+    pub fn new(spec:SignalSpec, duration:u64) -> Result<Self, AudioOutputError>
     {
-        let (device, config) = Self::get_config(spec);
+        let host = cpal::default_host();
+        let device = host.default_output_device()
+            .ok_or_else(|| AudioOutputError::DeviceError("Failed to get a default output device.".to_string()))?;
+        let (config, sample_format) = Self::get_config(&device, spec)?;
 
         let channels = spec.channels.count();
-        let ring_len = ((200 * spec.rate as usize) / 1000) * channels;
+        let resampler = if config.sample_rate.0 != spec.rate
+        {
+            Some(Resampler::new(spec.rate, config.sample_rate.0, channels)?)
+        }
+        else
+        {
+            None
+        };
+
+        let ring_len = ((200 * config.sample_rate.0 as usize) / 1000) * channels;
         let rb:SpscRb<f32> = SpscRb::new(ring_len);
         // Create the buffers for the stream.
         let writer = rb.producer();
         let reader = rb.consumer();
         let sample_buffer = SampleBuffer::<f32>::new(duration, spec);
 
-        let stream = device.build_output_stream(
-            &config,
-            move |data:&mut [f32], _:&cpal::OutputCallbackInfo| {
-                let written = reader.read(data).unwrap_or(0);
-                data[written..].iter_mut().for_each(|s| *s = 0.0);
-            },
-            move |err| {
-                panic!("ERR: An error occurred during the stream. {err}");
-            }
-        );
+        let gain = Arc::new(AtomicU32::new(1.0f32.to_bits()));
+        let closed = Arc::new(AtomicBool::new(false));
 
-        if let Err(err) = stream
-        { panic!("ERR: An error occurred when building the stream. {err}"); }
+        let stream = match sample_format
+        {
+            SampleFormat::I16 => Self::build_stream::<i16>(&device, &config, reader, gain.clone(), closed.clone()),
+            SampleFormat::U16 => Self::build_stream::<u16>(&device, &config, reader, gain.clone(), closed.clone()),
+            SampleFormat::F32 => Self::build_stream::<f32>(&device, &config, reader, gain.clone(), closed.clone()),
+            sample_format => Err(AudioOutputError::DeviceError(format!("Unsupported sample format '{sample_format}'.")))
+        }?;
 
-        let stream = stream.unwrap();
-        stream.play().expect("ERR: Failed to play the stream.");
+        stream.play().map_err(AudioOutputError::PlayStreamError)?;
 
-        CpalOutput
+        Ok(CpalOutput
         {
             _device: device,
-            _config: config,
-            _spec: spec,
+            config,
+            spec,
             stream,
             writer,
-            sample_buffer
+            ring_buffer: rb,
+            sample_buffer,
+            channels,
+            resampler,
+            gain,
+            closed
+        })
+    }
+
+    /// Sets the linear playback gain (`1.0` is unity). Takes effect inside the
+    /// CPAL callback on the next buffer, ramped to avoid clicks.
+    pub fn set_volume(&self, volume:f32)
+    {
+        self.gain.store(volume.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Pauses the underlying CPAL stream.
+    pub fn pause(&self) -> Result<(), AudioOutputError>
+    {
+        self.stream.pause().map_err(|_| AudioOutputError::StreamClosedError)
+    }
+
+    /// Resumes the underlying CPAL stream.
+    pub fn resume(&self) -> Result<(), AudioOutputError>
+    {
+        self.stream.play().map_err(|_| AudioOutputError::StreamClosedError)
+    }
+
+    /// Drops any buffered audio so samples from before a seek don't play afterwards.
+    ///
+    /// Dropping `pending` input alone isn't enough: `rubato`'s `SincFixedIn` also
+    /// keeps an internal sinc delay line carried over from previously resampled
+    /// audio, so the resampler is rebuilt from scratch rather than just cleared,
+    /// otherwise the first chunk after a seek would still be filtered using
+    /// pre-seek samples.
+    pub fn clear(&mut self) -> Result<(), AudioOutputError>
+    {
+        self.ring_buffer.clear();
+        if self.resampler.is_some()
+        {
+            self.resampler = Some(Resampler::new(self.spec.rate, self.config.sample_rate.0, self.channels)?);
         }
+
+        Ok(())
     }
 
-    /// Write the `AudioBufferRef` to the buffers.
-    pub fn write(&mut self, decoded:AudioBufferRef)
+    /// How much decoded audio is sitting in buffers rather than actually playing:
+    /// the ring buffer (at the device rate) plus whatever the resampler hasn't
+    /// turned into output yet (at the source rate).
+    pub fn buffered_duration(&self) -> Duration
     {
-        if decoded.frames() == 0 { return; }
+        let ring_frames = self.ring_buffer.count() / self.channels;
+        let mut buffered = Duration::from_secs_f64(ring_frames as f64 / self.config.sample_rate.0 as f64);
+
+        if let Some(resampler) = self.resampler.as_ref()
+        {
+            buffered += Duration::from_secs_f64(resampler.pending[0].len() as f64 / self.spec.rate as f64);
+        }
+
+        buffered
+    }
+
+    /// Pushes interleaved `samples` to the ring buffer, resampling first if the
+    /// device's rate doesn't match the source rate.
+    fn push(&mut self, samples:&[f32]) -> Result<(), AudioOutputError>
+    {
+        let resampled;
+        let mut samples = match self.resampler.as_mut()
+        {
+            None => samples,
+            Some(resampler) => {
+                resampled = interleave(&resampler.process(&deinterleave(samples, self.channels))?);
+                &resampled
+            }
+        };
 
-        // CPAL wants the audio interleaved.
-        self.sample_buffer.copy_interleaved_ref(decoded);
-        let mut samples = self.sample_buffer.samples();
-        
-        // Write the interleaved samples to the ring buffer which is output by CPAL.
         while let Some(written) = self.writer.write_blocking(samples)
         {
             samples = &samples[written..];
         }
+
+        Ok(())
     }
-}
\ No newline at end of file
+}
+
+impl AudioOutput for CpalOutput
+{
+    /// Write the `AudioBufferRef` to the buffers and set the playback gain to
+    /// `volume`, which the CPAL callback applies to everything it plays from then on.
+    fn write(&mut self, decoded:AudioBufferRef, volume:f32) -> Result<(), AudioOutputError>
+    {
+        if self.closed.load(Ordering::Relaxed) { return Err(AudioOutputError::StreamClosedError); }
+        if decoded.frames() == 0 { return Ok(()); }
+
+        self.set_volume(volume);
+
+        // CPAL wants the audio interleaved.
+        self.sample_buffer.copy_interleaved_ref(decoded);
+        let samples = self.sample_buffer.samples();
+        self.push(samples)?;
+
+        Ok(())
+    }
+
+    /// Drains the resampler's tail and blocks until the ring buffer has been
+    /// fully consumed by the audio thread, so the caller can pause the stream
+    /// without cutting off buffered audio.
+    fn flush(&mut self) -> Result<(), AudioOutputError>
+    {
+        if let Some(resampler) = self.resampler.as_mut()
+        {
+            let tail = interleave(&resampler.flush()?);
+            if !tail.is_empty()
+            {
+                let mut samples = tail.as_slice();
+                while let Some(written) = self.writer.write_blocking(samples)
+                {
+                    samples = &samples[written..];
+                }
+            }
+        }
+
+        while self.ring_buffer.count() > 0
+        {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        Ok(())
+    }
+}